@@ -1,14 +1,15 @@
 //! Things that run `cargo doc`.
 use anyhow::{format_err, Error};
-use crossbeam_channel::unbounded;
+use crossbeam_channel::{unbounded, RecvTimeoutError};
 use notify::{event::Event, Error as NError, RecursiveMode, Watcher};
 use qu::ick_use::*;
 use std::{
-    path::Path,
+    collections::HashSet,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
     process::{Command, Stdio},
     sync::Arc,
     thread,
-    time::Duration,
 };
 
 use crate::Config;
@@ -24,6 +25,10 @@ enum CargoMsg {
 }
 
 /// Run `cargo doc` once.
+///
+/// `stderr` is captured rather than inherited so a failure can be rendered in
+/// the browser; it is still echoed to our own stderr so the terminal keeps
+/// seeing it live.
 pub(crate) fn run(config: &Config) -> Result<(), Error> {
     let mut cmd = Command::new("cargo");
     cmd.arg("doc");
@@ -32,23 +37,140 @@ pub(crate) fn run(config: &Config) -> Result<(), Error> {
     }
     cmd.args(config.cargo_args.iter().map(|s| s.as_str()))
         .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit());
+        .stderr(Stdio::piped());
     log::debug!("running `{:?}`", cmd);
-    let status = cmd.status()?;
+    let mut child = cmd.spawn()?;
+    let mut stderr = BufReader::new(child.stderr.take().expect("stderr was piped"));
+    let mut output = String::new();
+    let mut line = String::new();
+    while stderr.read_line(&mut line)? != 0 {
+        eprint!("{}", line);
+        output.push_str(&line);
+        line.clear();
+    }
+    let status = child.wait()?;
     if status.success() {
         Ok(())
     } else {
         Err(format_err!(
-            "cargo doc failed with error code {:?}",
-            status.code()
+            "cargo doc failed with error code {:?}:\n{}",
+            status.code(),
+            output
         ))
     }
 }
 
+/// Returns true if a change to `path` can't affect the generated docs, so a
+/// rebuild it triggered on its own would just be wasted work.
+fn should_ignore(path: &Path, target_dir: &Path) -> bool {
+    if path.starts_with(target_dir) {
+        // events can still race in even though we ask `notify` not to watch this
+        return true;
+    }
+    let file_name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(n) => n,
+        // no file name (e.g. the watched dir itself) - nothing to rebuild for
+        None => return true,
+    };
+    // editor swap/temp files
+    if file_name.starts_with('.') || file_name.ends_with('~') || file_name.ends_with(".swp") {
+        return true;
+    }
+    // vim's atomic-save temp file
+    if file_name == "4913" {
+        return true;
+    }
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("rs") | Some("toml") | Some("md") => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::should_ignore;
+    use std::path::Path;
+
+    #[test]
+    fn ignores_paths_under_target_dir() {
+        let target_dir = Path::new("/repo/target");
+        assert!(should_ignore(
+            Path::new("/repo/target/doc/index.html"),
+            target_dir
+        ));
+    }
+
+    #[test]
+    fn ignores_editor_swap_and_temp_files() {
+        let target_dir = Path::new("/repo/target");
+        assert!(should_ignore(Path::new("/repo/src/lib.rs.swp"), target_dir));
+        assert!(should_ignore(Path::new("/repo/src/lib.rs~"), target_dir));
+        assert!(should_ignore(Path::new("/repo/4913"), target_dir));
+    }
+
+    #[test]
+    fn ignores_dotfiles() {
+        let target_dir = Path::new("/repo/target");
+        assert!(should_ignore(Path::new("/repo/.gitignore"), target_dir));
+    }
+
+    #[test]
+    fn ignores_non_source_extensions() {
+        let target_dir = Path::new("/repo/target");
+        assert!(should_ignore(Path::new("/repo/src/logo.png"), target_dir));
+    }
+
+    #[test]
+    fn does_not_ignore_source_files() {
+        let target_dir = Path::new("/repo/target");
+        assert!(!should_ignore(Path::new("/repo/src/lib.rs"), target_dir));
+        assert!(!should_ignore(Path::new("/repo/Cargo.toml"), target_dir));
+        assert!(!should_ignore(Path::new("/repo/README.md"), target_dir));
+    }
+}
+
+/// Run `cargo doc` and, if it succeeds, mark the docs ready and tell every
+/// connected browser to reload. Failed builds leave the docs marked as not
+/// ready and never reach the live-reload channel, so users never see a
+/// half-built page; the captured output is stashed on `config` so `handle`
+/// can show it instead.
+pub(crate) fn rebuild_and_notify(config: &Config) {
+    let _ = config.ready.send(false);
+    match run(config) {
+        Ok(()) => {
+            config.clear_build_error();
+            let _ = config.ready.send(true);
+            let _ = config.live_reload.send(());
+        }
+        Err(e) => {
+            log::error!("error running `cargo doc`: {:#}", e);
+            config.set_build_error(format!("{:#}", e));
+        }
+    }
+}
+
+/// Run once in the background without blocking the caller, for when
+/// `--watch` isn't enabled but startup still shouldn't wait on the build.
+pub(crate) fn spawn_one_shot_build(config: Arc<Config>) {
+    thread::spawn(move || rebuild_and_notify(&config));
+}
+
+/// A handle for requesting an out-of-band rebuild from outside the watch
+/// thread (e.g. in response to `SIGHUP`), bypassing the debounce window.
+#[derive(Clone)]
+pub(crate) struct RebuildTrigger(crossbeam_channel::Sender<CargoMsg>);
+
+impl RebuildTrigger {
+    pub(crate) fn trigger(&self) {
+        let _ = self.0.send(CargoMsg::Run);
+    }
+}
+
 /// Spawn a thread to run `cargo doc` when a file change is detected.
 ///
-/// Call the supplied callback to shutdown this thread.
-pub(crate) fn watch(config: Arc<Config>) -> Result<impl FnOnce()> {
+/// Returns a [`RebuildTrigger`] for requesting rebuilds out of band, and a
+/// callback to shutdown the spawned threads.
+pub(crate) fn watch(config: Arc<Config>) -> Result<(RebuildTrigger, impl FnOnce())> {
     let (tx, rx) = unbounded();
     let (run_tx, run_rx) = unbounded();
 
@@ -59,44 +181,75 @@ pub(crate) fn watch(config: Arc<Config>) -> Result<impl FnOnce()> {
             let _ = tx.send(NotifyMsg::Event(evt));
         }
     })?;
-    watcher
-        .watch(
-            config.metadata.workspace_root.as_std_path(),
-            RecursiveMode::Recursive,
-        )
-        .context(format!(
-            "error watching \"{}\"",
-            config.metadata.workspace_root
-        ))?;
-    for extra in config.watch.as_ref().unwrap().iter() {
+    {
+        let state = config.state.read().unwrap();
+        watcher
+            .watch(
+                state.metadata.workspace_root.as_std_path(),
+                RecursiveMode::Recursive,
+            )
+            .context(format!(
+                "error watching \"{}\"",
+                state.metadata.workspace_root
+            ))?;
+        for extra in config.watch.as_ref().unwrap().iter() {
+            watcher
+                .watch(Path::new(extra), RecursiveMode::Recursive)
+                .context(format!("error watching \"{}\"", extra))?;
+        }
         watcher
-            .watch(Path::new(extra), RecursiveMode::Recursive)
-            .context(format!("error watching \"{}\"", extra))?;
+            .unwatch(state.metadata.target_directory.as_std_path())
+            .context(format!(
+                "error unwatching \"{}\"",
+                state.metadata.target_directory
+            ))?;
     }
-    watcher
-        .unwatch(config.metadata.target_directory.as_std_path())
-        .context(format!(
-            "error unwatching \"{}\"",
-            config.metadata.target_directory
-        ))?;
-
-    // notify thread
+
+    // notify thread: collects raw events into a pending set keyed by path, and
+    // only asks for a rebuild once no new event has arrived for a quiet window.
+    // This stops a single editor save, which emits several create/modify/rename
+    // events, from kicking off several `cargo doc` runs.
     let notify_thread = thread::spawn({
         let run_tx = run_tx.clone();
+        let target_dir = config
+            .state
+            .read()
+            .unwrap()
+            .metadata
+            .target_directory
+            .as_std_path()
+            .to_owned();
+        let quiet_window = config.watch_debounce;
         move || {
             // move watcher here so it lives as long as we are handling messages.
             let _watcher = watcher;
+            let mut pending: HashSet<PathBuf> = HashSet::new();
             loop {
-                match rx.recv() {
+                let recv_result = if pending.is_empty() {
+                    rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+                } else {
+                    rx.recv_timeout(quiet_window)
+                };
+                match recv_result {
                     Ok(NotifyMsg::Event(Ok(evt))) => {
                         log::trace!("receive notify event {:?}", evt);
-                        let _ = run_tx.send(CargoMsg::Run);
+                        for path in evt.paths {
+                            if !should_ignore(&path, &target_dir) {
+                                pending.insert(path);
+                            }
+                        }
                     }
                     Ok(NotifyMsg::Event(Err(err))) => {
                         log::error!("`notify` reported error: {:#}", err);
                     }
                     // main thread is going away
-                    Ok(NotifyMsg::Shutdown) | Err(_) => break,
+                    Ok(NotifyMsg::Shutdown) => break,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                    Err(RecvTimeoutError::Timeout) => {
+                        log::trace!("{} path(s) settled, rebuilding", pending.len());
+                        pending.clear();
+                        let _ = run_tx.send(CargoMsg::Run);
+                    }
                 }
             }
         }
@@ -104,32 +257,21 @@ pub(crate) fn watch(config: Arc<Config>) -> Result<impl FnOnce()> {
 
     // cargo doc thread
     let cargo_thread = thread::spawn({
-        move || 'main: loop {
+        move || loop {
             match run_rx.recv() {
-                Ok(CargoMsg::Run) => {
-                    // debounce a little
-                    thread::sleep(Duration::from_millis(10));
-                    // drain the channel
-                    while let Ok(msg) = run_rx.try_recv() {
-                        if matches!(msg, CargoMsg::Shutdown) {
-                            break 'main;
-                        }
-                    }
-                    // rebuild docs
-                    if let Err(e) = run(&*config) {
-                        log::error!("error running `cargo doc`: {}", e);
-                    }
-                }
+                Ok(CargoMsg::Run) => rebuild_and_notify(&config),
                 Ok(CargoMsg::Shutdown) | Err(_) => break,
             }
         }
     });
 
-    Ok(move || {
+    let trigger = RebuildTrigger(run_tx.clone());
+
+    Ok((trigger, move || {
         // ignore errors since that means the thread is gone anyway.
         let _ = tx.send(NotifyMsg::Shutdown);
         let _ = run_tx.send(CargoMsg::Shutdown);
         notify_thread.join().unwrap();
         cargo_thread.join().unwrap();
-    })
+    }))
 }
@@ -0,0 +1,128 @@
+//! Browser live-reload over a WebSocket, in the style of mdBook's serve feature.
+use anyhow::Error;
+use hyper::{Body, Request, Response};
+use tokio::sync::broadcast;
+
+use crate::Result;
+
+/// Path the reload socket is served on.
+pub(crate) const PATH: &str = "/__docserve_livereload";
+
+/// Message sent down the socket when the browser should reload.
+const RELOAD_MSG: &str = "reload";
+
+/// Sender half of the broadcast channel that signals a successful rebuild.
+///
+/// Cloned into `Config` and handed to every connected websocket task; the
+/// `cargo_thread` in [`crate::cargo_doc::watch`] sends on it once a rebuild
+/// succeeds.
+pub(crate) type ReloadTx = broadcast::Sender<()>;
+
+/// Create a fresh reload channel sender.
+///
+/// The receiver count doesn't matter: `tokio::sync::broadcast` is fine with
+/// zero subscribers, and every websocket connection subscribes for itself.
+pub(crate) fn channel() -> ReloadTx {
+    broadcast::channel(16).0
+}
+
+/// The `<script>` injected before `</body>` in served HTML pages.
+fn script() -> String {
+    format!(
+        r#"<script>
+(function() {{
+  var proto = location.protocol === "https:" ? "wss:" : "ws:";
+  var socket = new WebSocket(proto + "//" + location.host + "{path}");
+  socket.onmessage = function(event) {{
+    if (event.data === "{msg}") {{
+      location.reload();
+    }}
+  }};
+}})();
+</script>"#,
+        path = PATH,
+        msg = RELOAD_MSG
+    )
+}
+
+/// If `body` contains a `</body>` tag, return a copy with the live-reload
+/// script inserted just before it. Otherwise the script is appended.
+pub(crate) fn inject(body: &[u8]) -> Vec<u8> {
+    let html = String::from_utf8_lossy(body);
+    let script = script();
+    let mut out = match html.rfind("</body>") {
+        Some(idx) => {
+            let mut out = String::with_capacity(html.len() + script.len());
+            out.push_str(&html[..idx]);
+            out.push_str(&script);
+            out.push_str(&html[idx..]);
+            out
+        }
+        None => format!("{}{}", html, script),
+    };
+    // avoid reallocating again below
+    out.shrink_to_fit();
+    out.into_bytes()
+}
+
+/// Upgrade `req` to a WebSocket and forward reload notifications to it until
+/// the socket closes.
+pub(crate) async fn handle(req: Request<Body>, reload: ReloadTx) -> Result<Response<Body>> {
+    let (response, websocket) = hyper_tungstenite::upgrade(req, None)?;
+
+    tokio::spawn(async move {
+        if let Err(e) = forward(websocket, reload).await {
+            log::debug!("livereload socket closed: {:#}", e);
+        }
+    });
+
+    Ok(response)
+}
+
+async fn forward(
+    websocket: hyper_tungstenite::HyperWebsocket,
+    reload: ReloadTx,
+) -> Result<(), Error> {
+    use futures_util::SinkExt;
+
+    let mut websocket = websocket.await?;
+    let mut rx = reload.subscribe();
+    loop {
+        // failed builds never send on this channel, so we never tell the
+        // browser to reload a half-built page.
+        if rx.recv().await.is_err() {
+            break;
+        }
+        websocket
+            .send(hyper_tungstenite::tungstenite::Message::text(RELOAD_MSG))
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::inject;
+
+    #[test]
+    fn inserts_script_before_closing_body_tag() {
+        let html = b"<html><body><h1>hi</h1></body></html>";
+        let out = String::from_utf8(inject(html)).unwrap();
+        let script_pos = out.find("<script>").unwrap();
+        let body_close_pos = out.find("</body>").unwrap();
+        assert!(script_pos < body_close_pos);
+    }
+
+    #[test]
+    fn appends_script_when_there_is_no_closing_body_tag() {
+        let html = b"<html><h1>no body tag here</h1></html>";
+        let out = String::from_utf8(inject(html)).unwrap();
+        assert!(out.ends_with("</script>"));
+    }
+
+    #[test]
+    fn appends_script_to_an_empty_body() {
+        let out = String::from_utf8(inject(b"")).unwrap();
+        assert!(out.starts_with("<script>"));
+    }
+}
@@ -12,11 +12,14 @@ use std::{
     convert::{Infallible, TryFrom, TryInto},
     net::SocketAddr,
     path::Path,
-    sync::Arc,
+    sync::{Arc, RwLock},
+    time::Duration,
 };
 use structopt::StructOpt;
+use tokio::sync::watch;
 
 mod cargo_doc;
+mod live_reload;
 
 pub type Result<T = (), E = anyhow::Error> = std::result::Result<T, E>;
 
@@ -34,6 +37,9 @@ struct Opt {
     /// Add an extra file or directory to be watched
     #[structopt(long = "watch-extra", name = "FILE")]
     watch_extra: Vec<String>,
+    /// How long to wait for filesystem events to settle before rebuilding docs, in milliseconds
+    #[structopt(long, default_value = "300")]
+    watch_debounce_ms: u64,
     /// Listen on all interfaces, not just localhost
     #[structopt(short = "P", long)]
     public: bool,
@@ -44,11 +50,7 @@ struct Opt {
 
 impl Opt {
     fn metadata(&self) -> Result<Metadata> {
-        let mut cmd = cargo_metadata::MetadataCommand::new();
-        if let Some(path) = self.manifest() {
-            cmd.manifest_path(path);
-        }
-        cmd.exec().map_err(Into::into)
+        fetch_metadata(self.manifest())
     }
 
     fn manifest(&self) -> Option<&Path> {
@@ -56,6 +58,33 @@ impl Opt {
     }
 }
 
+fn fetch_metadata(manifest: Option<&Path>) -> Result<Metadata> {
+    let mut cmd = cargo_metadata::MetadataCommand::new();
+    if let Some(path) = manifest {
+        cmd.manifest_path(path);
+    }
+    cmd.exec().map_err(Into::into)
+}
+
+/// The parts of [`Config`] that a `SIGHUP` re-reads: anything derived from
+/// `cargo_metadata`, which can change if the user edits the manifest or adds
+/// a workspace member without restarting the server.
+#[derive(Debug)]
+struct ConfigState {
+    /// Cargo metadata.
+    metadata: Metadata,
+    /// Location of output of `cargo doc`
+    doc_dir: String,
+}
+
+impl ConfigState {
+    fn new(manifest: Option<&Path>) -> Result<Self> {
+        let metadata = fetch_metadata(manifest)?;
+        let doc_dir = format!("{}/doc", metadata.target_directory);
+        Ok(ConfigState { metadata, doc_dir })
+    }
+}
+
 /// Build this from arguments.
 #[derive(Debug)]
 struct Config {
@@ -63,14 +92,25 @@ struct Config {
     address: SocketAddr,
     /// If None, don't watch. If Some, watch the files listed as well as src.
     watch: Option<Vec<String>>,
+    /// How long to wait for filesystem events to settle before rebuilding docs.
+    watch_debounce: Duration,
     /// Arguments to pass to `cargo doc`
     cargo_args: Vec<String>,
     /// The location of the manifest if it was supplied.
     manifest: Option<String>,
-    /// Cargo metadata.
-    metadata: Metadata,
-    /// Location of output of `cargo doc`
-    doc_dir: String,
+    /// Metadata and doc dir, re-read on `SIGHUP` so the server can pick up
+    /// new packages or a changed `doc_dir` without a restart.
+    state: RwLock<ConfigState>,
+    /// Broadcasts a message to every connected browser when a rebuild succeeds.
+    live_reload: live_reload::ReloadTx,
+    /// Whether `doc_dir` currently holds a complete, up-to-date build.
+    ///
+    /// Flipped to `false` before each rebuild and back to `true` only once it
+    /// succeeds, so `handle` never serves a half-built or stale page.
+    ready: watch::Sender<bool>,
+    /// The captured output of the most recent failed build, if any. Cleared
+    /// as soon as a later build succeeds.
+    build_error: RwLock<Option<String>>,
 }
 
 impl TryFrom<Opt> for Config {
@@ -88,8 +128,7 @@ impl TryFrom<Opt> for Config {
             opt.cargo_args.remove(0);
         }
 
-        let metadata = opt.metadata()?;
-        let doc_dir = format!("{}/doc", metadata.target_directory);
+        let state = ConfigState::new(opt.manifest())?;
         Ok(Config {
             address: (host, opt.port).into(),
             watch: if opt.watch {
@@ -98,24 +137,65 @@ impl TryFrom<Opt> for Config {
                 None
             },
             cargo_args: opt.cargo_args,
+            watch_debounce: Duration::from_millis(opt.watch_debounce_ms),
             manifest: opt.manifest,
-            metadata,
-            doc_dir,
+            state: RwLock::new(state),
+            live_reload: live_reload::channel(),
+            ready: watch::channel(false).0,
+            build_error: RwLock::new(None),
         })
     }
 }
 
 impl Config {
+    /// Location of output of `cargo doc`.
+    fn doc_dir(&self) -> String {
+        self.state.read().unwrap().doc_dir.clone()
+    }
+
+    /// Whether `doc_dir` currently holds a complete build.
+    fn is_ready(&self) -> bool {
+        *self.ready.borrow()
+    }
+
+    /// The captured output of the most recent failed build, if any.
+    fn build_error(&self) -> Option<String> {
+        self.build_error.read().unwrap().clone()
+    }
+
+    fn set_build_error(&self, error: String) {
+        *self.build_error.write().unwrap() = Some(error);
+    }
+
+    fn clear_build_error(&self) {
+        *self.build_error.write().unwrap() = None;
+    }
+
     /// Where to open the browser at.
     fn open_at(&self) -> Result<String> {
-        let name = self
+        let state = self.state.read().unwrap();
+        let name = state
             .metadata
             .root_package()
-            .or_else(|| self.metadata.packages.get(0))
+            .or_else(|| state.metadata.packages.get(0))
             .map(|pkg| pkg.name.replace('-', "_"))
             .ok_or_else(|| format_err!("could not find any packages"))?;
         Ok(format!("/{}/index.html", name))
     }
+
+    /// Re-read `cargo_metadata` and recompute `doc_dir`, picking up any
+    /// changes to the manifest since startup (new packages, a new
+    /// `target-dir`, and so on).
+    ///
+    /// This does not change which paths are watched: `watch` and the
+    /// `notify` watcher it drives are set up once from the CLI args at
+    /// startup, so new `--watch-extra` paths still need a restart to take
+    /// effect.
+    fn reload(&self) -> Result<()> {
+        let new_state = ConfigState::new(self.manifest.as_deref().map(Path::new))?;
+        *self.state.write().unwrap() = new_state;
+        Ok(())
+    }
 }
 
 #[qu::ick]
@@ -124,14 +204,17 @@ fn main(opt: Opt) -> Result<(), Error> {
     let config: Config = opt.try_into()?;
     let config = Arc::new(config);
     log::trace!("Config: {:?}", config);
-    log::debug!("Doc dir: {}", config.doc_dir);
-
-    cargo_doc::run(&config)?;
+    log::debug!("Doc dir: {}", config.doc_dir());
 
-    let shutdown = if config.watch.is_some() {
-        Some(cargo_doc::watch(config.clone())?)
+    // Bind and start serving straight away; `handle` shows a holding page
+    // until the first build finishes, so we don't make users wait on it here.
+    let (rebuild_trigger, shutdown) = if config.watch.is_some() {
+        let (trigger, shutdown) = cargo_doc::watch(config.clone())?;
+        trigger.trigger();
+        (Some(trigger), Some(shutdown))
     } else {
-        None
+        cargo_doc::spawn_one_shot_build(config.clone());
+        (None, None)
     };
 
     // serve target/doc
@@ -140,6 +223,8 @@ fn main(opt: Opt) -> Result<(), Error> {
         .enable_io()
         .build()?
         .block_on(async move {
+            spawn_sighup_handler(config.clone(), rebuild_trigger);
+
             let address = config.address;
             let make_service = make_service_fn(move |_conn| {
                 // clone for each connection
@@ -165,6 +250,63 @@ fn main(opt: Opt) -> Result<(), Error> {
     Ok(())
 }
 
+/// Listen for `SIGHUP` and, on receipt, reload `Config` from a fresh
+/// `cargo_metadata` call and trigger an immediate rebuild, so adding a
+/// dependency or workspace member doesn't require a restart. This does not
+/// pick up new `--watch-extra` paths; see [`Config::reload`].
+///
+/// A no-op on non-Unix targets, where there is no `SIGHUP` to listen for.
+#[cfg(unix)]
+fn spawn_sighup_handler(config: Arc<Config>, rebuild_trigger: Option<cargo_doc::RebuildTrigger>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::error!("failed to install SIGHUP handler: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            log::info!("SIGHUP received, reloading configuration");
+            // `reload` shells out to `cargo metadata`, which can take seconds
+            // on a cold cache; run it on a blocking thread rather than
+            // stalling this single-threaded runtime (and the HTTP server
+            // with it) for the duration, same as the rebuild below.
+            let reload_result = {
+                let config = config.clone();
+                tokio::task::spawn_blocking(move || config.reload()).await
+            };
+            match reload_result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    log::error!("failed to reload cargo metadata: {:#}", e);
+                    continue;
+                }
+                Err(e) => {
+                    log::error!("metadata reload task panicked: {:#}", e);
+                    continue;
+                }
+            }
+            // Rebuild through `rebuild_and_notify` (via the watch thread when
+            // there is one), which tells connected browsers to refresh once
+            // the build succeeds.
+            match &rebuild_trigger {
+                Some(trigger) => trigger.trigger(),
+                // No watch thread to hand this off to; run it on its own
+                // thread rather than blocking this single-threaded runtime
+                // (and the HTTP server with it) for the build's duration.
+                None => cargo_doc::spawn_one_shot_build(config.clone()),
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn spawn_sighup_handler(_config: Arc<Config>, _rebuild_trigger: Option<cargo_doc::RebuildTrigger>) {
+}
+
 async fn handle(config: Arc<Config>, req: Request<Body>) -> Result<Response<Body>> {
     if matches!((req.method(), req.uri().path()), (&Method::GET, "/")) {
         // Redirect "/" to the docs for the root package.
@@ -175,10 +317,149 @@ async fn handle(config: Arc<Config>, req: Request<Body>) -> Result<Response<Body
             .insert(header::LOCATION, HeaderValue::from_str(&redirect).unwrap());
         return Ok(res);
     }
-    Static::new(config.doc_dir.clone())
-        .serve(req)
-        .await
-        .map_err(Into::into)
+    if req.uri().path() == live_reload::PATH && hyper_tungstenite::is_upgrade_request(&req) {
+        return live_reload::handle(req, config.live_reload.clone()).await;
+    }
+    if req.uri().path() == STATUS_PATH {
+        return Ok(status_page(&config));
+    }
+    if !config.is_ready() && is_html_navigation(&req) {
+        return Ok(status_page(&config));
+    }
+    let res = Static::new(config.doc_dir()).serve(req).await?;
+    inject_live_reload(res).await
+}
+
+/// Route rendering the most recent build's status: the captured error output
+/// if the last build failed, or the holding page while one is in flight.
+const STATUS_PATH: &str = "/__docserve_status";
+
+/// Whether `req` looks like a browser loading a page, as opposed to a
+/// request for a CSS/JS/image asset referenced by one.
+fn is_html_navigation(req: &Request<Body>) -> bool {
+    req.method() == Method::GET
+        && req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.contains("text/html"))
+            .unwrap_or(false)
+}
+
+const BUILDING_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta http-equiv="refresh" content="2">
+<title>Building docs&hellip;</title>
+</head>
+<body>
+<h1>Building documentation&hellip;</h1>
+<p>This page will refresh automatically once the build finishes.</p>
+</body>
+</html>
+"#;
+
+/// The captured output of the last failed build, the holding page while one
+/// is in flight, or a confirmation that the docs are ready if neither. All
+/// carry the live-reload script, so a stale status page clears itself as
+/// soon as the next build succeeds; a failed build sends no reload.
+fn status_page(config: &Config) -> Response<Body> {
+    match config.build_error() {
+        Some(error) => error_page(&error),
+        None if config.is_ready() => ready_page(config),
+        None => html_page(BUILDING_PAGE),
+    }
+}
+
+/// Shown at [`STATUS_PATH`] once a build has succeeded and nothing is
+/// building, so the route doesn't claim docs are "building" forever.
+fn ready_page(config: &Config) -> Response<Body> {
+    let link = config.open_at().unwrap_or_else(|_| "/".to_owned());
+    let body = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Docs ready</title>
+</head>
+<body>
+<h1>Docs are up to date</h1>
+<p><a href="{}">Open the docs</a></p>
+</body>
+</html>
+"#,
+        link
+    );
+    html_page(&body)
+}
+
+/// An error page rendering `cargo doc`'s captured output, so a build failure
+/// that would otherwise only show up in the terminal is visible in-browser.
+fn error_page(error: &str) -> Response<Body> {
+    let body = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>cargo doc failed</title>
+</head>
+<body>
+<h1>cargo doc failed</h1>
+<pre>{}</pre>
+</body>
+</html>
+"#,
+        escape_html(error)
+    );
+    html_page(&body)
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Wrap `body` (a full HTML document) in a response with the live-reload
+/// script injected.
+fn html_page(body: &str) -> Response<Body> {
+    let body = live_reload::inject(body.as_bytes());
+    let mut res = Response::new(Body::from(body));
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    res
+}
+
+/// If `res` is a complete (`200 OK`) HTML document, buffer it and inject the
+/// live-reload `<script>` before `</body>` so the browser reloads itself
+/// after a rebuild.
+///
+/// `hyper-staticfile` also serves conditional/range requests as `304 Not
+/// Modified` or `206 Partial Content`; those must be left untouched; a 304
+/// can't carry a body at all, and a 206's body is only a slice of the file,
+/// not something we can safely splice a script into.
+async fn inject_live_reload(res: Response<Body>) -> Result<Response<Body>> {
+    let is_html = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("text/html"))
+        .unwrap_or(false);
+    if res.status() != StatusCode::OK || !is_html {
+        return Ok(res);
+    }
+
+    let (mut parts, body) = res.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let body = live_reload::inject(&body);
+    parts.headers.insert(
+        header::CONTENT_LENGTH,
+        HeaderValue::from_str(&body.len().to_string()).unwrap(),
+    );
+    Ok(Response::from_parts(parts, Body::from(body)))
 }
 
 /*